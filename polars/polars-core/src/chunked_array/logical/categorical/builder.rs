@@ -1,13 +1,16 @@
 use std::hash::{Hash, Hasher};
+use std::sync::Arc;
 
 use ahash::CallHasher;
 use arrow::array::*;
 use hashbrown::hash_map::RawEntryMut;
+use once_cell::sync::OnceCell;
 use polars_arrow::trusted_len::PushUnchecked;
 
+use super::mmap::MmapRevMapping;
 use crate::frame::groupby::hashing::HASHMAP_INIT_SIZE;
 use crate::prelude::*;
-use crate::{datatypes::PlHashMap, use_string_cache, StrHashGlobal, StringCache, POOL};
+use crate::{datatypes::PlHashMap, use_string_cache, StringCache, POOL};
 
 pub enum RevMappingBuilder {
     /// Hashmap: maps the indexes from the global cache/categorical array to indexes in the local Utf8Array
@@ -39,50 +42,86 @@ impl RevMappingBuilder {
     fn finish(self) -> RevMapping {
         use RevMappingBuilder::*;
         match self {
-            Local(b) => RevMapping::Local(b.into()),
-            GlobalFinished(map, b, uuid) => RevMapping::Global(map, b, uuid),
+            Local(b) => RevMapping::Local(b.into(), OnceCell::new()),
+            GlobalFinished(map, b, uuid) => RevMapping::Global(map, b, uuid, OnceCell::new()),
         }
     }
 }
 
+/// `hash(str) -> candidate category indices` index, built lazily the first
+/// time [`RevMapping::find`] is called and reused afterwards. `RevMapping` is
+/// immutable once built, so the index never needs to be invalidated.
+///
+/// Keyed by hash rather than by a borrowed `&str` so the index never has to
+/// fabricate a lifetime for string data it doesn't own: a candidate's hash
+/// can collide with another string's, so `find` still verifies the actual
+/// value by indexing back into the backing `Utf8Array` before accepting it.
+type ReverseLookup = OnceCell<PlHashMap<u64, Vec<u32>>>;
+
 #[derive(Clone, Debug)]
 pub enum RevMapping {
     /// Hashmap: maps the indexes from the global cache/categorical array to indexes in the local Utf8Array
     /// Utf8Array: caches the string values
-    Global(PlHashMap<u32, u32>, Utf8Array<i64>, u128),
+    Global(PlHashMap<u32, u32>, Utf8Array<i64>, u128, ReverseLookup),
     /// Utf8Array: caches the string values
-    Local(Utf8Array<i64>),
+    Local(Utf8Array<i64>, ReverseLookup),
+    /// Dictionary backed by a memory-mapped, checksummed on-disk file instead
+    /// of an in-process `Utf8Array`. `Arc`'d so cloning a `RevMapping` built
+    /// this way is just a refcount bump on the shared mmap.
+    Mmap(Arc<MmapRevMapping>),
 }
 
 impl Default for RevMapping {
     fn default() -> Self {
         let slice: &[Option<&str>] = &[];
-        RevMapping::Local(Utf8Array::<i64>::from(slice))
+        RevMapping::Local(Utf8Array::<i64>::from(slice), OnceCell::new())
     }
 }
 
 #[allow(clippy::len_without_is_empty)]
 impl RevMapping {
     pub fn is_global(&self) -> bool {
-        matches!(self, Self::Global(_, _, _))
+        matches!(self, Self::Global(_, _, _, _))
+    }
+
+    /// Write this dictionary to `path` as a memory-mappable file that
+    /// [`Self::mmap_from_path`] can later load without rebuilding it.
+    pub fn write_to_path<P: AsRef<std::path::Path>>(&self, path: P) -> Result<()> {
+        match self {
+            Self::Global(_, a, _, _) | Self::Local(a, _) => MmapRevMapping::write_to_path(a, path),
+            Self::Mmap(_) => {
+                let values: Vec<Option<&str>> = (0..self.len() as u32)
+                    .map(|idx| Some(self.get(idx)))
+                    .collect();
+                MmapRevMapping::write_to_path(&Utf8Array::<i64>::from(values), path)
+            }
+        }
+    }
+
+    /// Load a dictionary written by [`Self::write_to_path`], memory-mapping
+    /// the file so its values are never fully materialized in memory.
+    pub fn mmap_from_path<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        Ok(Self::Mmap(Arc::new(MmapRevMapping::mmap_from_path(path)?)))
     }
 
     /// Get the length of the [`RevMapping`]
     pub fn len(&self) -> usize {
         match self {
-            Self::Global(_, a, _) => a.len(),
-            Self::Local(a) => a.len(),
+            Self::Global(_, a, _, _) => a.len(),
+            Self::Local(a, _) => a.len(),
+            Self::Mmap(m) => m.len(),
         }
     }
 
     /// Categorical to str
     pub fn get(&self, idx: u32) -> &str {
         match self {
-            Self::Global(map, a, _) => {
+            Self::Global(map, a, _, _) => {
                 let idx = *map.get(&idx).unwrap();
                 a.value(idx as usize)
             }
-            Self::Local(a) => a.value(idx as usize),
+            Self::Local(a, _) => a.value(idx as usize),
+            Self::Mmap(m) => m.get(idx),
         }
     }
 
@@ -92,44 +131,84 @@ impl RevMapping {
     /// This doesn't do any bound checking
     pub(crate) unsafe fn get_unchecked(&self, idx: u32) -> &str {
         match self {
-            Self::Global(map, a, _) => {
+            Self::Global(map, a, _, _) => {
                 let idx = *map.get(&idx).unwrap();
                 a.value_unchecked(idx as usize)
             }
-            Self::Local(a) => a.value_unchecked(idx as usize),
+            Self::Local(a, _) => a.value_unchecked(idx as usize),
+            Self::Mmap(m) => m.get_unchecked(idx),
         }
     }
     /// Check if the categoricals are created under the same global string cache.
     pub fn same_src(&self, other: &Self) -> bool {
         match (self, other) {
-            (RevMapping::Global(_, _, l), RevMapping::Global(_, _, r)) => *l == *r,
-            (RevMapping::Local(l), RevMapping::Local(r)) => {
+            (RevMapping::Global(_, _, l, _), RevMapping::Global(_, _, r, _)) => *l == *r,
+            (RevMapping::Local(l, _), RevMapping::Local(r, _)) => {
                 std::ptr::eq(l as *const Utf8Array<_>, r as *const Utf8Array<_>)
             }
+            (RevMapping::Mmap(l), RevMapping::Mmap(r)) => Arc::ptr_eq(l, r),
             _ => false,
         }
     }
 
+    /// Build the `hash(str) -> local indices` lookup used by the `Local` variant.
+    fn build_local_index(a: &Utf8Array<i64>) -> PlHashMap<u64, Vec<u32>> {
+        let hb = StringCache::get_hash_builder();
+        let mut index: PlHashMap<u64, Vec<u32>> = PlHashMap::with_capacity(a.len());
+        for (local_idx, s) in a.values_iter().enumerate() {
+            let h = str::get_hash(s, &hb);
+            index.entry(h).or_default().push(local_idx as u32);
+        }
+        index
+    }
+
+    /// Build the `hash(str) -> global indices` lookup used by the `Global`
+    /// variant, by inverting the existing `global -> local` map.
+    fn build_global_index(map: &PlHashMap<u32, u32>, a: &Utf8Array<i64>) -> PlHashMap<u64, Vec<u32>> {
+        let hb = StringCache::get_hash_builder();
+        let mut index: PlHashMap<u64, Vec<u32>> = PlHashMap::with_capacity(map.len());
+        for (&global_idx, &local_idx) in map.iter() {
+            let s = a.value(local_idx as usize);
+            let h = str::get_hash(s, &hb);
+            index.entry(h).or_default().push(global_idx);
+        }
+        index
+    }
+
     /// str to Categorical
     pub fn find(&self, value: &str) -> Option<u32> {
+        let hb = StringCache::get_hash_builder();
+        let h = str::get_hash(value, &hb);
         match self {
-            Self::Global(map, a, _) => {
-                map.iter()
-                    // Safety:
-                    // value is always within bounds
-                    .find(|(_k, &v)| (unsafe { a.value_unchecked(v as usize) } == value))
-                    .map(|(k, _v)| *k)
+            Self::Global(map, a, _, cached) => {
+                // Building the index only reads `map`/`a`, it never mutates
+                // the global cache, so flag this thread as doing a read-only
+                // pass over it for the diagnostics build.
+                let _readonly = crate::STRING_CACHE.readonly_guard();
+                let index = cached.get_or_init(|| Self::build_global_index(map, a));
+                // A hash collision can put more than one candidate under the
+                // same key, so verify each one against the real value before
+                // accepting it.
+                index
+                    .get(&h)?
+                    .iter()
+                    .copied()
+                    .find(|&global_idx| a.value(*map.get(&global_idx).unwrap() as usize) == value)
             }
-            Self::Local(a) => {
-                // Safety: within bounds
-                unsafe { (0..a.len()).find(|idx| a.value_unchecked(*idx) == value) }
-                    .map(|idx| idx as u32)
+            Self::Local(a, cached) => {
+                let index = cached.get_or_init(|| Self::build_local_index(a));
+                index
+                    .get(&h)?
+                    .iter()
+                    .copied()
+                    .find(|&local_idx| a.value(local_idx as usize) == value)
             }
+            Self::Mmap(m) => m.find(value),
         }
     }
 }
 
-#[derive(Eq, Copy, Clone)]
+#[derive(Eq, Copy, Clone, Debug)]
 pub struct StrHashLocal<'a> {
     str: &'a str,
     hash: u64,
@@ -229,44 +308,21 @@ impl CategoricalChunkedBuilder {
         // locally we don't need a hashmap because we all categories are 1 integer apart
         // so the index is local, and the values is global
         let mut local_to_global: Vec<u32> = Vec::with_capacity(values.len());
-        let id;
 
-        // now we have to lock the global string cache.
-        // we will create a mapping from our local categoricals to global categoricals
-        // and a mapping from global categoricals to our local categoricals
-
-        // in a separate scope so that we drop the global cache as soon as we are finished
-        {
-            let cache = &mut crate::STRING_CACHE.lock_map();
-            id = cache.uuid;
-            let global_mapping = &mut cache.map;
-            let hb = global_mapping.hasher().clone();
-
-            for s in values.values_iter() {
-                let h = str::get_hash(s, &hb);
-                let mut global_idx = global_mapping.len() as u32;
-                // Note that we don't create the StrHashGlobal to search the key in the hashmap
-                // as StrHashGlobal may allocate a string
-                let entry = global_mapping
-                    .raw_entry_mut()
-                    .from_hash(h, |val| (val.hash == h) && val.str == s);
-
-                match entry {
-                    RawEntryMut::Occupied(entry) => global_idx = *entry.get(),
-                    RawEntryMut::Vacant(entry) => {
-                        // only just now we allocate the string
-                        let key = StrHashGlobal::new(s.into(), h);
-                        entry.insert_with_hasher(h, key, global_idx, |s| s.hash);
-                    }
-                }
-                // safety:
-                // we allocated enough
-                unsafe { local_to_global.push_unchecked(global_idx) }
-            }
-            if global_mapping.len() > u32::MAX as usize {
-                panic!("not more than {} categories supported", u32::MAX)
-            };
+        // We will create a mapping from our local categoricals to global
+        // categoricals and a mapping from global categoricals to our local
+        // categoricals. The global string cache is sharded, so interning
+        // each value only locks the one shard it hashes into, instead of
+        // the whole cache.
+        let hb = StringCache::get_hash_builder();
+        for s in values.values_iter() {
+            let h = str::get_hash(s, &hb);
+            let global_idx = crate::STRING_CACHE.insert_with_hash(s, h);
+            // safety:
+            // we allocated enough
+            unsafe { local_to_global.push_unchecked(global_idx) }
         }
+        let id = crate::STRING_CACHE.uuid();
         // we now know the exact size
         // no reallocs
         let mut global_to_local = PlHashMap::with_capacity(local_to_global.len());
@@ -309,9 +365,10 @@ impl CategoricalChunkedBuilder {
         let mut local_to_global: Vec<u32>;
         let id;
 
-        // now we have to lock the global string cache.
         // we will create a mapping from our local categoricals to global categoricals
-        // and a mapping from global categoricals to our local categoricals
+        // and a mapping from global categoricals to our local categoricals. Each
+        // value is interned through the sharded global string cache, so values that
+        // hash into different shards can be inserted without contending on a single lock.
         let values: Utf8Array<_> =
             if let RevMappingBuilder::Local(values) = &mut self.reverse_mapping {
                 debug_assert_eq!(hashes.len(), values.len());
@@ -322,36 +379,13 @@ impl CategoricalChunkedBuilder {
                 unreachable!()
             };
 
-        // in a separate scope so that we drop the global cache as soon as we are finished
-        {
-            let cache = &mut crate::STRING_CACHE.lock_map();
-            id = cache.uuid;
-            let global_mapping = &mut cache.map;
-
-            for (s, h) in values.values_iter().zip(hashes.into_iter()) {
-                let mut global_idx = global_mapping.len() as u32;
-                // Note that we don't create the StrHashGlobal to search the key in the hashmap
-                // as StrHashGlobal may allocate a string
-                let entry = global_mapping
-                    .raw_entry_mut()
-                    .from_hash(h, |val| (val.hash == h) && val.str == s);
-
-                match entry {
-                    RawEntryMut::Occupied(entry) => global_idx = *entry.get(),
-                    RawEntryMut::Vacant(entry) => {
-                        // only just now we allocate the string
-                        let key = StrHashGlobal::new(s.into(), h);
-                        entry.insert_with_hasher(h, key, global_idx, |s| s.hash);
-                    }
-                }
-                // safety:
-                // we allocated enough
-                unsafe { local_to_global.push_unchecked(global_idx) }
-            }
-            if global_mapping.len() > u32::MAX as usize {
-                panic!("not more than {} categories supported", u32::MAX)
-            };
+        for (s, h) in values.values_iter().zip(hashes.into_iter()) {
+            let global_idx = crate::STRING_CACHE.insert_with_hash(s, h);
+            // safety:
+            // we allocated enough
+            unsafe { local_to_global.push_unchecked(global_idx) }
         }
+        id = crate::STRING_CACHE.uuid();
         // we now know the exact size
         // no reallocs
         let mut global_to_local = PlHashMap::with_capacity(local_to_global.len());
@@ -398,6 +432,10 @@ impl CategoricalChunkedBuilder {
 }
 
 fn fill_global_to_local(local_to_global: &[u32], global_to_local: &mut PlHashMap<u32, u32>) {
+    // We don't mutate the global cache here, only read the indices it already
+    // handed out, so flag this thread as doing a read-only pass for the
+    // diagnostics build; the guard lowers the flag again once it's dropped.
+    let _readonly = crate::STRING_CACHE.readonly_guard();
     let mut local_idx = 0;
     #[allow(clippy::explicit_counter_loop)]
     for global_idx in local_to_global {
@@ -479,4 +517,27 @@ mod test {
             assert_eq!(s.str_value(2), "world");
         }
     }
+
+    #[test]
+    fn rev_mapping_find_agrees_with_get() {
+        let _lock = SINGLE_LOCK.lock();
+        for global in &[false, true] {
+            reset_string_cache();
+            toggle_string_cache(*global);
+
+            let mut builder = CategoricalChunkedBuilder::new("a", 10);
+            builder.drain_iter(vec![Some("foo"), Some("bar"), Some("foo"), None, Some("baz")]);
+            let ca = builder.finish();
+            let rev_map = ca.get_rev_map();
+
+            // every present value round-trips: `find` must return the same
+            // index `get` maps it back from.
+            for idx in 0..rev_map.len() as u32 {
+                let s = rev_map.get(idx);
+                assert_eq!(rev_map.find(s), Some(idx));
+            }
+            // a value that was never inserted is not found.
+            assert_eq!(rev_map.find("quux"), None);
+        }
+    }
 }
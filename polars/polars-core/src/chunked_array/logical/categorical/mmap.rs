@@ -0,0 +1,318 @@
+//! On-disk, memory-mappable format for a [`RevMapping`](super::builder::RevMapping)
+//! dictionary, modelled after immutable sorted-string tables: values are
+//! stored sorted in fixed-size blocks so `find` can binary-search the block
+//! index instead of scanning, and the whole file can be `mmap`'d so the
+//! `Utf8Array` values never have to be materialized in process memory.
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use arrow::array::Utf8Array;
+use crc32c::crc32c;
+use memmap2::Mmap;
+
+use crate::error::PolarsError;
+use crate::prelude::*;
+
+/// Number of (sorted) string values packed into each block when *writing* a
+/// new dictionary file. Stored in the footer so a file written with a
+/// different value (e.g. by a different process/version) is still chunked
+/// correctly on load instead of silently mis-read -- see
+/// [`MmapRevMapping::mmap_from_path`].
+const BLOCK_LEN: usize = 128;
+const MAGIC: &[u8; 8] = b"PLRVMAP1";
+const FOOTER_MAGIC: &[u8; 8] = b"PLRVFOOT";
+const FOOTER_LEN: usize = 8 + 8 + 8 + 4 + 4 + 4 + 8;
+
+/// A `RevMapping` backing store that lives in a memory-mapped file instead of
+/// an in-process `Utf8Array`. Multiple processes (or multiple queries in this
+/// one) can share a single mmap instead of each building and holding its own
+/// copy of the dictionary.
+pub(crate) struct MmapRevMapping {
+    mmap: Mmap,
+    /// (file offset, byte length) of each block's string data, not counting
+    /// its trailing checksum.
+    block_spans: Vec<(u64, u64)>,
+    /// First (smallest) key of each block, kept resident for binary search.
+    block_first_key: Vec<Box<str>>,
+    /// checksum of a block is only verified the first time it's touched.
+    block_verified: Vec<AtomicBool>,
+    /// Number of (sorted) string values packed into each block, read back
+    /// from the file's footer. Chunking is driven by this rather than by
+    /// the compiled-in `BLOCK_LEN`, so a dictionary written by a process
+    /// with a different `BLOCK_LEN` is still read correctly instead of
+    /// silently mis-chunked.
+    block_len: usize,
+    /// categorical index -> position in the sorted/block order.
+    cat_to_sorted: Vec<u32>,
+    /// position in the sorted/block order -> categorical index.
+    sorted_to_cat: Vec<u32>,
+}
+
+impl std::fmt::Debug for MmapRevMapping {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("MmapRevMapping")
+            .field("len", &self.sorted_to_cat.len())
+            .field("n_blocks", &self.block_spans.len())
+            .finish()
+    }
+}
+
+impl MmapRevMapping {
+    pub(crate) fn len(&self) -> usize {
+        self.sorted_to_cat.len()
+    }
+
+    /// Decode the `sorted_idx`'th value, verifying (and caching) that
+    /// block's checksum the first time it's touched.
+    fn value_at_sorted_idx(&self, sorted_idx: usize) -> &str {
+        let block_idx = sorted_idx / self.block_len;
+        let pos_in_block = sorted_idx % self.block_len;
+        let (offset, len) = self.block_spans[block_idx];
+        let block = &self.mmap[offset as usize..(offset as usize + len as usize)];
+
+        if !self.block_verified[block_idx].load(Ordering::Acquire) {
+            let crc_bytes = &self.mmap[offset as usize + len as usize..offset as usize + len as usize + 4];
+            let expected = u32::from_le_bytes(crc_bytes.try_into().unwrap());
+            let actual = crc32c(block);
+            assert_eq!(
+                actual, expected,
+                "corrupt categorical dictionary: checksum mismatch in block {block_idx}"
+            );
+            self.block_verified[block_idx].store(true, Ordering::Release);
+        }
+
+        let mut cursor = 0usize;
+        for _ in 0..pos_in_block {
+            let len = u32::from_le_bytes(block[cursor..cursor + 4].try_into().unwrap()) as usize;
+            cursor += 4 + len;
+        }
+        let len = u32::from_le_bytes(block[cursor..cursor + 4].try_into().unwrap()) as usize;
+        cursor += 4;
+        // Safety: we only ever write valid utf8 into the blocks.
+        unsafe { std::str::from_utf8_unchecked(&block[cursor..cursor + len]) }
+    }
+
+    pub(crate) fn get(&self, idx: u32) -> &str {
+        let sorted_idx = self.cat_to_sorted[idx as usize] as usize;
+        self.value_at_sorted_idx(sorted_idx)
+    }
+
+    /// # Safety
+    /// `idx` must be in bounds.
+    pub(crate) unsafe fn get_unchecked(&self, idx: u32) -> &str {
+        let sorted_idx = *self.cat_to_sorted.get_unchecked(idx as usize) as usize;
+        self.value_at_sorted_idx(sorted_idx)
+    }
+
+    /// Binary search the block index for `value`, then linear-scan the
+    /// (small) candidate block.
+    pub(crate) fn find(&self, value: &str) -> Option<u32> {
+        // the last block whose first key is <= `value`
+        let block_idx = match self.block_first_key.binary_search_by(|k| k.as_ref().cmp(value)) {
+            Ok(i) => i,
+            Err(0) => return None,
+            Err(i) => i - 1,
+        };
+        let start = block_idx * self.block_len;
+        let end = (start + self.block_len).min(self.sorted_to_cat.len());
+        (start..end)
+            .find(|&sorted_idx| self.value_at_sorted_idx(sorted_idx) == value)
+            .map(|sorted_idx| self.sorted_to_cat[sorted_idx])
+    }
+
+    pub(crate) fn write_to_path<P: AsRef<Path>>(values: &Utf8Array<i64>, path: P) -> Result<()> {
+        let n_entries = values.len();
+        let mut sorted: Vec<u32> = (0..n_entries as u32).collect();
+        sorted.sort_unstable_by_key(|&idx| values.value(idx as usize));
+
+        let mut cat_to_sorted = vec![0u32; n_entries];
+        for (sorted_idx, &cat_idx) in sorted.iter().enumerate() {
+            cat_to_sorted[cat_idx as usize] = sorted_idx as u32;
+        }
+
+        let file = File::create(path).map_err(PolarsError::Io)?;
+        let mut writer = BufWriter::new(file);
+        let pos = std::cell::Cell::new(0u64);
+        let write_all = |writer: &mut BufWriter<File>, buf: &[u8]| -> Result<()> {
+            writer.write_all(buf).map_err(PolarsError::Io)?;
+            pos.set(pos.get() + buf.len() as u64);
+            Ok(())
+        };
+
+        write_all(&mut writer, MAGIC)?;
+
+        let mut block_index = Vec::with_capacity(sorted.len() / BLOCK_LEN + 1);
+        for block in sorted.chunks(BLOCK_LEN) {
+            let block_start = pos.get();
+            let first_key = values.value(block[0] as usize);
+            let mut block_bytes = Vec::new();
+            for &cat_idx in block {
+                let s = values.value(cat_idx as usize);
+                block_bytes.extend_from_slice(&(s.len() as u32).to_le_bytes());
+                block_bytes.extend_from_slice(s.as_bytes());
+            }
+            write_all(&mut writer, &block_bytes)?;
+            let crc = crc32c(&block_bytes);
+            write_all(&mut writer, &crc.to_le_bytes())?;
+            block_index.push((block_start, block_bytes.len() as u64, first_key.to_owned()));
+        }
+
+        let index_offset = pos.get();
+        for (offset, len, first_key) in &block_index {
+            write_all(&mut writer, &offset.to_le_bytes())?;
+            write_all(&mut writer, &len.to_le_bytes())?;
+            write_all(&mut writer, &(first_key.len() as u32).to_le_bytes())?;
+            write_all(&mut writer, first_key.as_bytes())?;
+        }
+
+        let cat_to_sorted_offset = pos.get();
+        for v in &cat_to_sorted {
+            write_all(&mut writer, &v.to_le_bytes())?;
+        }
+
+        let sorted_to_cat_offset = pos.get();
+        for &v in &sorted {
+            write_all(&mut writer, &v.to_le_bytes())?;
+        }
+
+        write_all(&mut writer, &index_offset.to_le_bytes())?;
+        write_all(&mut writer, &cat_to_sorted_offset.to_le_bytes())?;
+        write_all(&mut writer, &sorted_to_cat_offset.to_le_bytes())?;
+        write_all(&mut writer, &(block_index.len() as u32).to_le_bytes())?;
+        write_all(&mut writer, &(n_entries as u32).to_le_bytes())?;
+        write_all(&mut writer, &(BLOCK_LEN as u32).to_le_bytes())?;
+        write_all(&mut writer, FOOTER_MAGIC)?;
+
+        writer.flush().map_err(PolarsError::Io)?;
+        Ok(())
+    }
+
+    pub(crate) fn mmap_from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path).map_err(PolarsError::Io)?;
+        // Safety: the file is not expected to be mutated while mapped; that's
+        // the caller's responsibility, same as for any other mmap'd file.
+        let mmap = unsafe { Mmap::map(&file) }.map_err(PolarsError::Io)?;
+
+        let err = || PolarsError::ComputeError("corrupt categorical dictionary file".into());
+        if mmap.len() < MAGIC.len() + FOOTER_LEN || &mmap[..MAGIC.len()] != MAGIC {
+            return Err(err());
+        }
+        let footer = &mmap[mmap.len() - FOOTER_LEN..];
+        if &footer[FOOTER_LEN - 8..] != FOOTER_MAGIC {
+            return Err(err());
+        }
+        let read_u64 = |b: &[u8]| u64::from_le_bytes(b[..8].try_into().unwrap());
+        let read_u32 = |b: &[u8]| u32::from_le_bytes(b[..4].try_into().unwrap());
+
+        let index_offset = read_u64(&footer[0..]) as usize;
+        let cat_to_sorted_offset = read_u64(&footer[8..]) as usize;
+        let sorted_to_cat_offset = read_u64(&footer[16..]) as usize;
+        let n_blocks = read_u32(&footer[24..]) as usize;
+        let n_entries = read_u32(&footer[28..]) as usize;
+        let block_len = read_u32(&footer[32..]) as usize;
+        // A block size of 0 can't have produced `n_blocks` non-empty blocks
+        // unless there are no entries at all; guard against it explicitly
+        // since it would otherwise divide-by-zero in `value_at_sorted_idx`.
+        if block_len == 0 && n_entries > 0 {
+            return Err(err());
+        }
+
+        let mut cursor = index_offset;
+        let mut block_spans = Vec::with_capacity(n_blocks);
+        let mut block_first_key = Vec::with_capacity(n_blocks);
+        for _ in 0..n_blocks {
+            let offset = read_u64(&mmap[cursor..]);
+            cursor += 8;
+            let len = read_u64(&mmap[cursor..]);
+            cursor += 8;
+            let key_len = read_u32(&mmap[cursor..]) as usize;
+            cursor += 4;
+            let key = std::str::from_utf8(&mmap[cursor..cursor + key_len])
+                .map_err(|_| err())?
+                .to_owned()
+                .into_boxed_str();
+            cursor += key_len;
+            block_spans.push((offset, len));
+            block_first_key.push(key);
+        }
+
+        let mut cat_to_sorted = Vec::with_capacity(n_entries);
+        let mut cursor = cat_to_sorted_offset;
+        for _ in 0..n_entries {
+            cat_to_sorted.push(read_u32(&mmap[cursor..]));
+            cursor += 4;
+        }
+
+        let mut sorted_to_cat = Vec::with_capacity(n_entries);
+        let mut cursor = sorted_to_cat_offset;
+        for _ in 0..n_entries {
+            sorted_to_cat.push(read_u32(&mmap[cursor..]));
+            cursor += 4;
+        }
+
+        let block_verified = (0..n_blocks).map(|_| AtomicBool::new(false)).collect();
+
+        Ok(Self {
+            mmap,
+            block_spans,
+            block_first_key,
+            block_verified,
+            block_len,
+            cat_to_sorted,
+            sorted_to_cat,
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        let mut path = std::env::temp_dir();
+        path.push(format!(
+            "polars-mmap-rev-mapping-test-{name}-{:?}",
+            std::thread::current().id()
+        ));
+        path
+    }
+
+    #[test]
+    fn write_and_mmap_round_trip() {
+        let path = temp_path("round-trip");
+        let values: Vec<Option<&str>> = vec![Some("banana"), Some("apple"), Some("cherry"), Some("apple")];
+        let arr = Utf8Array::<i64>::from(values.clone());
+
+        MmapRevMapping::write_to_path(&arr, &path).unwrap();
+        let mapping = MmapRevMapping::mmap_from_path(&path).unwrap();
+
+        assert_eq!(mapping.len(), values.len());
+        for (idx, v) in values.iter().enumerate() {
+            assert_eq!(mapping.get(idx as u32), v.unwrap());
+        }
+        assert_eq!(mapping.find("cherry"), Some(2));
+        assert_eq!(mapping.find("missing"), None);
+
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    #[should_panic(expected = "checksum mismatch")]
+    fn corrupted_block_fails_checksum_verification() {
+        let path = temp_path("corruption");
+        let values: Vec<Option<&str>> = vec![Some("one"), Some("two"), Some("three")];
+        let arr = Utf8Array::<i64>::from(values);
+        MmapRevMapping::write_to_path(&arr, &path).unwrap();
+
+        // Flip a byte inside the first block's data, after the MAGIC header.
+        let mut bytes = std::fs::read(&path).unwrap();
+        let corrupt_at = MAGIC.len() + 4;
+        bytes[corrupt_at] ^= 0xFF;
+        std::fs::write(&path, &bytes).unwrap();
+
+        let mapping = MmapRevMapping::mmap_from_path(&path).unwrap();
+        mapping.get(0);
+    }
+}
@@ -0,0 +1,458 @@
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use ahash::CallHasher;
+use hashbrown::hash_map::RawEntryMut;
+use once_cell::sync::Lazy;
+use parking_lot::Mutex;
+
+use crate::datatypes::PlHashMap;
+
+#[cfg(feature = "string_cache_diagnostics")]
+mod diagnostics {
+    use std::cell::Cell;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    use parking_lot::Mutex;
+
+    /// Written on either side of the cache storage; if either no longer
+    /// reads back as `CANARY` something wrote past the end of an adjacent
+    /// buffer.
+    const CANARY: u64 = 0xC0FFEE_DEAD_BEEF_u64;
+    /// Journal is a ring buffer: old entries are dropped once it's full, we
+    /// only need enough history to diagnose the event that just happened.
+    const JOURNAL_CAP: usize = 256;
+
+    thread_local! {
+        // Whether *this* thread is currently doing a read-only pass over the
+        // cache. Deliberately thread-local rather than a shared flag: the
+        // concurrent-build scenario `StringCache`'s sharding exists to
+        // support means some other, unrelated thread can legitimately be
+        // inserting into the cache at the same moment a read-only pass runs
+        // on this one, and that must not be mistaken for corruption.
+        static READONLY: Cell<bool> = const { Cell::new(false) };
+    }
+
+    #[derive(Clone, Debug)]
+    pub(crate) enum Event {
+        Insert(u32),
+        Lookup(u32),
+        Reset(u128),
+    }
+
+    /// RAII guard marking the current thread as doing a read-only pass over
+    /// the cache (e.g. `fill_global_to_local`, `RevMapping::find`) for as
+    /// long as it's alive. Lowers the flag again on drop, including on an
+    /// unwinding panic.
+    pub(crate) struct ReadonlyGuard {
+        _private: (),
+    }
+
+    impl ReadonlyGuard {
+        fn new() -> Self {
+            READONLY.with(|r| r.set(true));
+            Self { _private: () }
+        }
+    }
+
+    impl Drop for ReadonlyGuard {
+        fn drop(&mut self) {
+            READONLY.with(|r| r.set(false));
+        }
+    }
+
+    /// Wraps the cache with an audit trail: a canary on either side of the
+    /// storage to catch buffer overruns, a bounded journal of every insert,
+    /// lookup and reset so corruption can be traced back to the operation
+    /// that caused it, and
+    /// the expected uuid from the last reset, so a mutation that shows up
+    /// under a stale/mismatched generation is caught instead of silently
+    /// attributed to the current one.
+    pub(crate) struct Diagnostics {
+        canary_before: AtomicU64,
+        journal: Mutex<Vec<Event>>,
+        expected_uuid: AtomicU64,
+        canary_after: AtomicU64,
+    }
+
+    impl Default for Diagnostics {
+        fn default() -> Self {
+            Self {
+                canary_before: AtomicU64::new(CANARY),
+                journal: Mutex::new(Vec::with_capacity(JOURNAL_CAP)),
+                expected_uuid: AtomicU64::new(0),
+                canary_after: AtomicU64::new(CANARY),
+            }
+        }
+    }
+
+    impl Diagnostics {
+        pub(crate) fn validate(&self, uuid: u128) {
+            let before = self.canary_before.load(Ordering::Acquire);
+            let after = self.canary_after.load(Ordering::Acquire);
+            if before != CANARY || after != CANARY {
+                self.dump_and_panic(uuid, "canary overwritten, likely an adjacent buffer overrun");
+            }
+            let expected = self.expected_uuid.load(Ordering::Acquire) as u128;
+            if uuid != expected {
+                self.dump_and_panic(
+                    uuid,
+                    &format!("uuid mismatch: cache reports {uuid} but the last recorded reset was {expected}"),
+                );
+            }
+        }
+
+        pub(crate) fn assert_not_readonly(&self, uuid: u128) {
+            if READONLY.with(|r| r.get()) {
+                self.dump_and_panic(uuid, "mutation attempted on a thread with an active read-only guard");
+            }
+        }
+
+        pub(crate) fn record(&self, event: Event) {
+            if let Event::Reset(uuid) = event {
+                self.expected_uuid.store(uuid as u64, Ordering::Release);
+            }
+            let mut journal = self.journal.lock();
+            if journal.len() == JOURNAL_CAP {
+                journal.remove(0);
+            }
+            journal.push(event);
+        }
+
+        pub(crate) fn readonly_guard() -> ReadonlyGuard {
+            ReadonlyGuard::new()
+        }
+
+        fn dump_and_panic(&self, uuid: u128, reason: &str) -> ! {
+            let journal = self.journal.lock();
+            panic!(
+                "string cache corruption detected: {reason}\ncurrent uuid = {uuid}\nlast {} journal entries: {:#?}",
+                journal.len(),
+                *journal,
+            );
+        }
+    }
+}
+
+#[cfg(feature = "string_cache_diagnostics")]
+pub(crate) type ReadonlyGuard = diagnostics::ReadonlyGuard;
+
+/// No-op stand-in so call sites don't need to `#[cfg]` the guard itself.
+#[cfg(not(feature = "string_cache_diagnostics"))]
+pub(crate) struct ReadonlyGuard;
+
+/// The process-wide global string cache used by `Categorical` types. It is
+/// only ever cleared by [`reset_string_cache`].
+pub static STRING_CACHE: Lazy<StringCache> = Lazy::new(Default::default);
+
+/// Used to lock the string cache during tests so that tests on the global
+/// cache don't interfere with each other.
+pub static SINGLE_LOCK: Mutex<()> = Mutex::new(());
+
+static USE_STRING_CACHE: AtomicU32 = AtomicU32::new(0);
+
+/// Check whether global string cache is used.
+pub fn use_string_cache() -> bool {
+    USE_STRING_CACHE.load(Ordering::Acquire) > 0
+}
+
+/// Enable/disable the global string cache used by `Categorical` types.
+pub fn toggle_string_cache(toggle: bool) {
+    if toggle {
+        USE_STRING_CACHE.store(1, Ordering::Release);
+    } else {
+        USE_STRING_CACHE.store(0, Ordering::Release);
+    }
+}
+
+/// Reset the global string cache, invalidating every `RevMapping` built
+/// under the previous generation.
+pub fn reset_string_cache() {
+    STRING_CACHE.clear()
+}
+
+#[derive(Eq)]
+pub(crate) struct StrHashGlobal {
+    pub(crate) str: String,
+    pub(crate) hash: u64,
+}
+
+impl Hash for StrHashGlobal {
+    fn hash<H: Hasher>(&self, state: &mut H) {
+        state.write_u64(self.hash)
+    }
+}
+
+impl PartialEq for StrHashGlobal {
+    fn eq(&self, other: &Self) -> bool {
+        (self.hash == other.hash) && (self.str == other.str)
+    }
+}
+
+impl StrHashGlobal {
+    pub(crate) fn new(s: String, hash: u64) -> Self {
+        Self { str: s, hash }
+    }
+}
+
+/// Number of shards the global string cache is split into. Must be a power
+/// of two so `shard_for` can mask the hash instead of taking a modulo.
+const N_SHARDS: usize = 64;
+const SHARD_BITS: u32 = N_SHARDS.trailing_zeros();
+
+/// A sharded, concurrent string -> global-index interner.
+///
+/// Every shard owns its own `PlHashMap` and `Mutex`, so strings that hash
+/// into different shards can be interned fully in parallel. A single
+/// `AtomicU32` counter hands out globally-unique indices across all shards,
+/// which is the one invariant every shard must agree on: once a string has
+/// been assigned an index, that mapping never changes, so we always probe
+/// the shard before inserting rather than inserting speculatively.
+pub struct StringCache {
+    shards: Vec<Mutex<PlHashMap<StrHashGlobal, u32>>>,
+    counter: AtomicU32,
+    // Bumped every time the cache is cleared so `RevMapping::same_src` can
+    // tell whether two categoricals were built under the same generation.
+    uuid: AtomicU64,
+    #[cfg(feature = "string_cache_diagnostics")]
+    diagnostics: diagnostics::Diagnostics,
+}
+
+impl Default for StringCache {
+    fn default() -> Self {
+        let shards = (0..N_SHARDS).map(|_| Mutex::new(Default::default())).collect();
+        Self {
+            shards,
+            counter: AtomicU32::new(0),
+            uuid: AtomicU64::new(0),
+            #[cfg(feature = "string_cache_diagnostics")]
+            diagnostics: Default::default(),
+        }
+    }
+}
+
+impl StringCache {
+    /// The hasher used everywhere a string needs to be hashed consistently
+    /// with the global cache (local categorical builders included).
+    pub(crate) fn get_hash_builder() -> ahash::RandomState {
+        ahash::RandomState::with_seeds(0, 0, 0, 0)
+    }
+
+    fn shard_for(hash: u64) -> usize {
+        // Use the top bits: the low bits are what hashbrown uses to pick a
+        // slot within a shard's own table, so this keeps the two decisions
+        // independent.
+        ((hash >> (64 - SHARD_BITS)) as usize) & (N_SHARDS - 1)
+    }
+
+    /// Intern `s`, returning its global categorical index. If `s` has never
+    /// been seen before it is assigned the next index, otherwise the
+    /// existing index is returned. Only the shard `s` hashes into is locked.
+    pub(crate) fn insert(&self, s: &str) -> u32 {
+        let hb = Self::get_hash_builder();
+        let hash = str::get_hash(s, &hb);
+        self.insert_with_hash(s, hash)
+    }
+
+    /// Same as [`Self::insert`] but reuses a hash computed by the caller
+    /// with [`Self::get_hash_builder`].
+    pub(crate) fn insert_with_hash(&self, s: &str, hash: u64) -> u32 {
+        let mut shard = self.shards[Self::shard_for(hash)].lock();
+        // Only run the diagnostics checks once this shard is locked: `clear`
+        // holds every shard's lock for as long as it takes to bump `uuid`
+        // and record the matching `Reset` event, so checking here instead of
+        // before the lock means we can never observe the two mid-update --
+        // either we run before `clear` takes this shard's lock, or after it
+        // has released every one of them, never in between.
+        #[cfg(feature = "string_cache_diagnostics")]
+        {
+            self.diagnostics.validate(self.uuid());
+            self.diagnostics.assert_not_readonly(self.uuid());
+        }
+
+        let entry = shard
+            .raw_entry_mut()
+            .from_hash(hash, |val| (val.hash == hash) && val.str == s);
+
+        let idx = match entry {
+            RawEntryMut::Occupied(entry) => {
+                let idx = *entry.get();
+                #[cfg(feature = "string_cache_diagnostics")]
+                self.diagnostics.record(diagnostics::Event::Lookup(idx));
+                idx
+            }
+            RawEntryMut::Vacant(entry) => {
+                // Only allocate the global index (and the owned `String`)
+                // once we know we actually need to insert.
+                let idx = self.counter.fetch_add(1, Ordering::Relaxed);
+                if idx == u32::MAX {
+                    panic!("not more than {} categories supported", u32::MAX)
+                }
+                let key = StrHashGlobal::new(s.into(), hash);
+                entry.insert_with_hasher(hash, key, idx, |s| s.hash);
+                #[cfg(feature = "string_cache_diagnostics")]
+                self.diagnostics.record(diagnostics::Event::Insert(idx));
+                idx
+            }
+        };
+        idx
+    }
+
+    pub(crate) fn uuid(&self) -> u128 {
+        self.uuid.load(Ordering::Acquire) as u128
+    }
+
+    /// Mark the current thread as doing a read-only pass over the cache for
+    /// as long as the returned guard is alive, e.g. while inverting
+    /// `global_to_local` or probing `RevMapping::find`. Scoped to this
+    /// thread only: some other thread may legitimately be inserting into the
+    /// cache at the same time (that's the concurrent-build scenario the
+    /// sharding exists to support) without that being a sign of corruption.
+    /// Only has any effect when built with the `string_cache_diagnostics`
+    /// feature; a no-op guard otherwise.
+    #[cfg(feature = "string_cache_diagnostics")]
+    pub(crate) fn readonly_guard(&self) -> ReadonlyGuard {
+        diagnostics::Diagnostics::readonly_guard()
+    }
+
+    #[cfg(not(feature = "string_cache_diagnostics"))]
+    pub(crate) fn readonly_guard(&self) -> ReadonlyGuard {
+        ReadonlyGuard
+    }
+
+    /// Clears every shard and bumps the generation `uuid`. Takes every
+    /// shard's lock at once (in a fixed, ascending order, the same order
+    /// `shard_for` would ever be probed in, so this can never deadlock
+    /// against `insert_with_hash`, which only ever holds a single shard's
+    /// lock at a time) so the reset is atomic: no concurrent insert can land
+    /// in an already-cleared shard with an index that collides with one
+    /// still live in a shard this call hasn't gotten to yet.
+    fn clear(&self) {
+        // Held until the end of this function, past the `uuid` bump and the
+        // matching diagnostics record below: `insert_with_hash` now runs its
+        // diagnostics checks only after locking its own shard, so as long as
+        // every shard stays locked for the whole of this critical section no
+        // other thread can observe `uuid` and the diagnostics' recorded
+        // generation out of step with each other.
+        let mut guards: Vec<_> = self.shards.iter().map(|shard| shard.lock()).collect();
+        for shard in guards.iter_mut() {
+            shard.clear();
+        }
+        self.counter.store(0, Ordering::Relaxed);
+        self.uuid.fetch_add(1, Ordering::AcqRel);
+        #[cfg(feature = "string_cache_diagnostics")]
+        self.diagnostics.record(diagnostics::Event::Reset(self.uuid()));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+    use std::sync::Arc;
+
+    use super::*;
+
+    #[test]
+    fn insert_is_unique_across_shards_under_concurrency() {
+        let _guard = SINGLE_LOCK.lock();
+        let cache = Arc::new(StringCache::default());
+
+        let handles: Vec<_> = (0..8)
+            .map(|t| {
+                let cache = Arc::clone(&cache);
+                std::thread::spawn(move || {
+                    (0..200)
+                        .map(|i| cache.insert(&format!("thread-{t}-val-{i}")))
+                        .collect::<Vec<_>>()
+                })
+            })
+            .collect();
+
+        let mut seen = HashSet::new();
+        for handle in handles {
+            for idx in handle.join().unwrap() {
+                assert!(seen.insert(idx), "global index {idx} was handed out twice");
+            }
+        }
+        assert_eq!(seen.len(), 8 * 200);
+    }
+
+    #[test]
+    fn insert_same_string_from_many_threads_agrees_on_one_index() {
+        let _guard = SINGLE_LOCK.lock();
+        let cache = Arc::new(StringCache::default());
+
+        let handles: Vec<_> = (0..16)
+            .map(|_| {
+                let cache = Arc::clone(&cache);
+                std::thread::spawn(move || cache.insert("shared"))
+            })
+            .collect();
+
+        let indices: HashSet<u32> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(indices.len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "not more than")]
+    fn insert_panics_past_u32_max_categories() {
+        let cache = StringCache::default();
+        cache.counter.store(u32::MAX - 1, Ordering::Relaxed);
+        cache.insert("one-before-the-limit");
+        // The cache is now out of indices; this one must panic instead of
+        // silently wrapping back around to an index already in use.
+        cache.insert("pushes-past-the-limit");
+    }
+}
+
+#[cfg(all(test, feature = "string_cache_diagnostics"))]
+mod diagnostics_test {
+    use super::*;
+
+    #[test]
+    fn readonly_guard_does_not_affect_other_threads() {
+        let _guard = SINGLE_LOCK.lock();
+        let cache = std::sync::Arc::new(StringCache::default());
+
+        let readonly = cache.readonly_guard();
+        let other = {
+            let cache = std::sync::Arc::clone(&cache);
+            std::thread::spawn(move || cache.insert("inserted-from-another-thread"))
+        };
+        // Must not panic: the readonly guard only applies to this thread.
+        other.join().unwrap();
+        drop(readonly);
+    }
+
+    #[test]
+    #[should_panic(expected = "read-only guard")]
+    fn insert_while_readonly_on_same_thread_panics() {
+        let _guard = SINGLE_LOCK.lock();
+        let cache = StringCache::default();
+        let _readonly = cache.readonly_guard();
+        cache.insert("mutating while marked read-only");
+    }
+
+    #[test]
+    fn concurrent_clear_and_insert_never_report_a_false_uuid_mismatch() {
+        let _guard = SINGLE_LOCK.lock();
+        let cache = std::sync::Arc::new(StringCache::default());
+
+        let inserter = {
+            let cache = std::sync::Arc::clone(&cache);
+            std::thread::spawn(move || {
+                for i in 0..500 {
+                    // Must never panic with "uuid mismatch": `clear` only
+                    // ever exposes `uuid` and its recorded `Reset` event to
+                    // other threads together, never the former without the
+                    // latter.
+                    cache.insert(&format!("val-{i}"));
+                }
+            })
+        };
+        for _ in 0..50 {
+            cache.clear();
+        }
+        inserter.join().unwrap();
+    }
+}
@@ -1,30 +1,65 @@
+use std::hash::{Hash, Hasher};
+use std::sync::atomic::{AtomicU64, Ordering};
+
 use parking_lot::Mutex;
 use polars_core::prelude::*;
 
 use crate::prelude::file_caching::FileFingerPrint;
 use crate::prelude::*;
 
+/// Number of shards the cache is split into. Readers of different files
+/// usually hash into different shards, so they rarely block each other.
+const N_SHARDS: usize = 16;
+
+struct CacheEntry {
+    read_count: FileCount,
+    df: DataFrame,
+    size: u64,
+    // Bumped on every `read`, used to find the least-recently-read entry
+    // when the cache is over its byte budget.
+    last_used: u64,
+}
+
 #[derive(Clone)]
 pub(crate) struct FileCache {
-    // (path, predicate) -> (read_count, df)
-    inner: Arc<PlHashMap<FileFingerPrint, Mutex<(FileCount, DataFrame)>>>,
+    // (path, predicate) -> (read_count, df), sharded by fingerprint hash.
+    shards: Arc<Vec<Mutex<PlHashMap<FileFingerPrint, CacheEntry>>>>,
+    // Optional upper bound on the total estimated size of cached `DataFrame`s.
+    // `None` means unbounded, i.e. only the `read_count == total_read_count`
+    // trigger below ever frees memory.
+    budget: Option<u64>,
+    bytes_used: Arc<AtomicU64>,
+    tick: Arc<AtomicU64>,
 }
 
 impl FileCache {
-    pub(super) fn new(finger_prints: Option<Vec<FileFingerPrint>>) -> Self {
-        let inner = match finger_prints {
-            None => Arc::new(Default::default()),
-            Some(fps) => {
-                let mut mapping = PlHashMap::with_capacity(fps.len());
-                for fp in fps {
-                    mapping.insert(fp, Mutex::new((0, Default::default())));
-                }
-                Arc::new(mapping)
-            }
-        };
+    pub(super) fn new(_finger_prints: Option<Vec<FileFingerPrint>>) -> Self {
+        // Fingerprints are no longer pre-registered: entries are created on
+        // demand the first time `read` sees a given fingerprint.
+        let shards = (0..N_SHARDS).map(|_| Mutex::new(Default::default())).collect();
+        Self {
+            shards: Arc::new(shards),
+            budget: None,
+            bytes_used: Arc::new(AtomicU64::new(0)),
+            tick: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    /// Bound the cache to (approximately) `budget_bytes` of cached `DataFrame`
+    /// memory, evicting the least-recently-read entries once it's exceeded.
+    /// A builder method rather than a `new` parameter so existing call sites
+    /// that only ever constructed an unbounded cache don't have to change.
+    pub(super) fn with_budget(mut self, budget_bytes: u64) -> Self {
+        self.budget = Some(budget_bytes);
+        self
+    }
 
-        Self { inner }
+    fn shard_for(finger_print: &FileFingerPrint) -> usize {
+        let mut hasher = ahash::AHasher::default();
+        finger_print.hash(&mut hasher);
+        (hasher.finish() as usize) & (N_SHARDS - 1)
     }
+
     pub(crate) fn read<F>(
         &self,
         finger_print: FileFingerPrint,
@@ -38,24 +73,164 @@ impl FileCache {
             if total_read_count == 0 {
                 eprintln!("we have hit an unexpected branch, please open an issue")
             }
-            reader()
-        } else {
-            // should exist
-            let guard = self.inner.get(&finger_print).unwrap();
-            let mut state = guard.lock();
+            return reader();
+        }
+
+        let tick = self.tick.fetch_add(1, Ordering::Relaxed);
+        let out = {
+            let shard_idx = Self::shard_for(&finger_print);
+            let mut shard = self.shards[shard_idx].lock();
+
+            // Insert on first sight of this fingerprint instead of panicking
+            // on an unregistered one.
+            let entry = shard.entry(finger_print.clone()).or_insert_with(|| CacheEntry {
+                read_count: 0,
+                df: Default::default(),
+                size: 0,
+                last_used: tick,
+            });
 
             // initialize df
-            if state.0 == 0 {
-                state.1 = reader()?;
+            if entry.read_count == 0 {
+                entry.df = reader()?;
+                entry.size = entry.df.estimated_size() as u64;
+                self.bytes_used.fetch_add(entry.size, Ordering::Relaxed);
             }
-            state.0 += 1;
+            entry.read_count += 1;
+            entry.last_used = tick;
 
-            // remove dataframe from memory
-            if state.0 == total_read_count {
-                Ok(std::mem::take(&mut state.1))
+            // remove dataframe from memory once every reader has seen it
+            if entry.read_count == total_read_count {
+                let entry = shard.remove(&finger_print).unwrap();
+                self.bytes_used.fetch_sub(entry.size, Ordering::Relaxed);
+                Ok(entry.df)
             } else {
-                Ok(state.1.clone())
+                Ok(entry.df.clone())
+            }
+        };
+
+        self.evict_over_budget();
+        out
+    }
+
+    /// LRU fallback eviction: only kicks in when a byte budget was set and
+    /// the `read_count == total_read_count` trigger above hasn't freed
+    /// enough memory on its own.
+    fn evict_over_budget(&self) {
+        let budget = match self.budget {
+            Some(budget) => budget,
+            None => return,
+        };
+
+        while self.bytes_used.load(Ordering::Relaxed) > budget {
+            let oldest = self
+                .shards
+                .iter()
+                .enumerate()
+                .filter_map(|(shard_idx, shard)| {
+                    shard
+                        .lock()
+                        .iter()
+                        .map(|(fp, entry)| (entry.last_used, shard_idx, fp.clone()))
+                        .min_by_key(|(last_used, _, _)| *last_used)
+                })
+                .min_by_key(|(last_used, _, _)| *last_used);
+
+            let (_, shard_idx, finger_print) = match oldest {
+                Some(oldest) => oldest,
+                // nothing left to evict, the budget just doesn't fit a single entry
+                None => break,
+            };
+
+            if let Some(entry) = self.shards[shard_idx].lock().remove(&finger_print) {
+                self.bytes_used.fetch_sub(entry.size, Ordering::Relaxed);
             }
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use std::path::PathBuf;
+    use std::sync::atomic::AtomicUsize;
+
+    use super::*;
+
+    fn fp(id: usize) -> FileFingerPrint {
+        FileFingerPrint {
+            path: PathBuf::from(format!("/tmp/file-cache-test-{id}.parquet")),
+            predicate: None,
+            slice: (0, None),
+        }
+    }
+
+    fn df_with_value(val: i32) -> DataFrame {
+        DataFrame::new(vec![Series::new("v", vec![val; 1000])]).unwrap()
+    }
+
+    #[test]
+    fn concurrent_reads_of_distinct_fingerprints_are_not_miscounted() {
+        let cache = Arc::new(FileCache::new(None));
+
+        let handles: Vec<_> = (0..8)
+            .map(|i| {
+                let cache = Arc::clone(&cache);
+                std::thread::spawn(move || {
+                    let calls = AtomicUsize::new(0);
+                    for _ in 0..2 {
+                        let df = cache
+                            .read(fp(i), 2, &mut || {
+                                calls.fetch_add(1, Ordering::Relaxed);
+                                Ok(df_with_value(i as i32))
+                            })
+                            .unwrap();
+                        assert_eq!(df.column("v").unwrap().i32().unwrap().get(0).unwrap(), i as i32);
+                    }
+                    calls.load(Ordering::Relaxed)
+                })
+            })
+            .collect();
+
+        // Every thread only ever reads its own fingerprint, so sharding must
+        // never mix up entries between them, and the reader closure must run
+        // exactly once per fingerprint (the second read is served from cache).
+        for handle in handles {
+            assert_eq!(handle.join().unwrap(), 1);
+        }
+    }
+
+    #[test]
+    fn drop_on_last_read_still_holds_with_sharding() {
+        let cache = FileCache::new(None);
+        let calls = AtomicUsize::new(0);
+
+        for _ in 0..3 {
+            cache
+                .read(fp(0), 3, &mut || {
+                    calls.fetch_add(1, Ordering::Relaxed);
+                    Ok(df_with_value(1))
+                })
+                .unwrap();
+        }
+
+        // the reader closure must only ever run once: every read after the
+        // first is served from cache until the last of the 3 readers sees it,
+        // at which point the entry is dropped instead of lingering.
+        assert_eq!(calls.load(Ordering::Relaxed), 1);
+        assert_eq!(cache.bytes_used.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn evict_over_budget_frees_memory_once_exceeded() {
+        let cache = FileCache::new(None).with_budget(1);
+
+        for i in 0..5 {
+            cache.read(fp(i), 2, &mut || Ok(df_with_value(i as i32))).unwrap();
+        }
+
+        // A budget of 1 byte can't fit even a single entry, so the fallback
+        // eviction must have kept reclaiming memory after every insert
+        // instead of letting usage grow unbounded across all 5 fingerprints.
+        assert_eq!(cache.bytes_used.load(Ordering::Relaxed), 0);
+    }
+}